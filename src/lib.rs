@@ -1,10 +1,19 @@
 use std::string::ToString;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
-use rand::prelude::*;
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::net::{TcpStream, ToSocketAddrs};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot, Mutex};
+
+/// Bound of each connection's outgoing job channel; also the value that
+/// `mpsc::Sender::capacity()` is subtracted from to get a queue depth.
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// Per-line read timeout when draining a `HELP` probe response.
+const HELP_PROBE_TIMEOUT: Duration = Duration::from_millis(300);
 
 /// A 2D vector.
 #[derive(Copy, Clone, Debug)]
@@ -27,6 +36,7 @@ pub enum Cmd {
     Size,
     SetPx(Vec2, Rgb),
     GetPx(Vec2),
+    Offset(Vec2),
 }
 
 impl ToString for Cmd {
@@ -41,13 +51,66 @@ impl ToString for Cmd {
                 coordinate.0, coordinate.1, rgb.0, rgb.1, rgb.2
             ),
             GetPx(coordinate) => format!("PX {} {}", coordinate.0, coordinate.1),
+            Offset(coordinate) => format!("OFFSET {} {}", coordinate.0, coordinate.1),
+        }
+    }
+}
+
+/// The wire format used to serialize `Cmd`s to a Pixelflut server.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CmdEncoding {
+    /// The original text protocol, e.g. `PX x y RRGGBB\n`.
+    Ascii,
+    /// The `PB` binary protocol supported by some high-performance servers:
+    /// `PB` magic, x/y as little-endian `u16`s, then R, G, B, A bytes.
+    Binary,
+}
+
+impl Cmd {
+    /// Encodes this command to wire bytes using the given encoding. Falls
+    /// back to the ASCII form for anything other than an in-range `SetPx`.
+    pub fn encode(&self, encoding: CmdEncoding) -> Vec<u8> {
+        match (encoding, *self) {
+            (CmdEncoding::Binary, Cmd::SetPx(Vec2(x, y), Rgb(r, g, b)))
+                if x <= u16::MAX as u32 && y <= u16::MAX as u32 =>
+            {
+                let mut buf = Vec::with_capacity(8);
+                buf.extend_from_slice(b"PB");
+                buf.extend_from_slice(&(x as u16).to_le_bytes());
+                buf.extend_from_slice(&(y as u16).to_le_bytes());
+                buf.extend_from_slice(&[r, g, b, 0xff]);
+                buf
+            }
+            _ => {
+                let mut buf = self.to_string().into_bytes();
+                buf.push(b'\n');
+                buf
+            }
         }
     }
 }
 
+/// The priority tier a `Job` is enqueued at. Each connection drains its
+/// `High` queue before touching its `Low` queue, so interactive/important
+/// paints (e.g. differential repair) preempt bulk background fills.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RequestPriority {
+    Low,
+    High,
+}
+
+/// A unit of work queued onto a booted `Sock`'s channel: either a
+/// fire-and-forget command, or a command whose response should be sent back
+/// over a oneshot channel once the round trip completes.
+pub enum Job {
+    Fire(Cmd),
+    Request(Cmd, oneshot::Sender<Result<Rgb>>),
+}
+
 /// A connection to a Pixelflut server.
 pub struct Sock {
     inner: BufReader<TcpStream>,
+    encoding: CmdEncoding,
 }
 
 impl Sock {
@@ -59,7 +122,10 @@ impl Sock {
         let stream = TcpStream::connect(addr).await?;
         let buf = BufReader::new(stream);
 
-        Ok(Self { inner: buf })
+        Ok(Self {
+            inner: buf,
+            encoding: CmdEncoding::Ascii,
+        })
     }
 
     /// Reads a line from the server.
@@ -69,27 +135,130 @@ impl Sock {
         Ok(response)
     }
 
-    /// Sends a command to the server.
+    /// Sends a command to the server, using this socket's negotiated `CmdEncoding`.
     pub async fn send(&mut self, cmd: Cmd) -> Result<()> {
-        let cmd = cmd.to_string() + "\n";
-        self.inner.write_all(cmd.as_bytes()).await?;
+        let bytes = cmd.encode(self.encoding);
+        self.inner.write_all(&bytes).await?;
         self.inner.flush().await?;
         Ok(())
     }
 
-    /// Consumes this `Sock` in order to spawn a channel that is used to send commands to the inner socket.
-    pub fn boot(mut self) -> mpsc::Sender<Cmd> {
-        let (tx, mut rx): (mpsc::Sender<Cmd>, mpsc::Receiver<_>) = mpsc::channel(1024);
+    /// Probes a throwaway connection's `HELP` output for `PB` support, falling
+    /// back to `CmdEncoding::Ascii` on any failure, timeout, or lack thereof.
+    pub async fn negotiate_encoding<A>(addr: A) -> Result<CmdEncoding>
+    where
+        A: ToSocketAddrs,
+    {
+        let supports_binary = async {
+            let mut sock = Sock::connect(addr).await?;
+            sock.send(Cmd::Help).await?;
+
+            let mut saw_pb = false;
+            loop {
+                match tokio::time::timeout(HELP_PROBE_TIMEOUT, sock.read_line()).await {
+                    Ok(Ok(line)) if !line.is_empty() => {
+                        if line.to_ascii_uppercase().contains("PB") {
+                            saw_pb = true;
+                        }
+                    }
+                    // A closed connection (an empty read), a read error, or a
+                    // drained response (no more lines within the timeout) all
+                    // mean HELP output is done.
+                    _ => break,
+                }
+            }
+
+            Result::<bool>::Ok(saw_pb)
+        }
+        .await
+        .unwrap_or(false);
+
+        Ok(if supports_binary {
+            CmdEncoding::Binary
+        } else {
+            CmdEncoding::Ascii
+        })
+    }
+
+    /// Sends a `GetPx` command and parses the `PX x y RRGGBB` response.
+    pub async fn request(&mut self, cmd: Cmd) -> Result<Rgb> {
+        self.send(cmd).await?;
+        let response = self.read_line().await?;
+
+        let mut split = response.trim_end().splitn(4, ' ').skip(1);
+        split.next(); // x
+        split.next(); // y
+        let color = split
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("server gave no color in response to {:?}", cmd))?;
+
+        if color.len() != 6 {
+            anyhow::bail!(
+                "server gave malformed color {:?} in response to {:?}",
+                color,
+                cmd
+            );
+        }
+
+        let r = u8::from_str_radix(&color[0..2], 16)?;
+        let g = u8::from_str_radix(&color[2..4], 16)?;
+        let b = u8::from_str_radix(&color[4..6], 16)?;
+
+        Ok(Rgb(r, g, b))
+    }
+
+    /// Consumes this `Sock` in order to spawn a priority-aware pair of
+    /// channels used to send commands to the inner socket: the task always
+    /// drains the high-priority channel before touching the low-priority
+    /// one. Returns `(high_tx, low_tx, counters)`.
+    pub fn boot(mut self) -> (mpsc::Sender<Job>, mpsc::Sender<Job>, Arc<ConnCounters>) {
+        let (high_tx, mut high_rx): (mpsc::Sender<Job>, mpsc::Receiver<_>) =
+            mpsc::channel(CHANNEL_CAPACITY);
+        let (low_tx, mut low_rx): (mpsc::Sender<Job>, mpsc::Receiver<_>) =
+            mpsc::channel(CHANNEL_CAPACITY);
+        let counters = Arc::new(ConnCounters::default());
+        let task_counters = Arc::clone(&counters);
 
         tokio::spawn(async move {
-            while let Some(cmd) = rx.recv().await {
-                if let Err(err) = self.send(cmd).await {
-                    eprintln!("failed to send cmd: {:?}", err);
+            loop {
+                let job = tokio::select! {
+                    biased;
+                    job = high_rx.recv() => job,
+                    job = low_rx.recv() => job,
+                };
+                let Some(job) = job else {
+                    break;
+                };
+
+                match job {
+                    Job::Fire(cmd) => match self.send(cmd).await {
+                        Ok(()) => {
+                            task_counters.sent.fetch_add(1, Ordering::Relaxed);
+                        }
+                        Err(err) => {
+                            task_counters.dropped.fetch_add(1, Ordering::Relaxed);
+                            eprintln!("failed to send cmd: {:?}", err);
+                        }
+                    },
+                    Job::Request(cmd, reply) => {
+                        let result = self.request(cmd).await;
+                        match &result {
+                            Ok(_) => {
+                                task_counters.sent.fetch_add(1, Ordering::Relaxed);
+                            }
+                            Err(err) => {
+                                task_counters.dropped.fetch_add(1, Ordering::Relaxed);
+                                eprintln!("failed to request {:?}: {:?}", cmd, err);
+                            }
+                        }
+                        // The requester may have given up waiting; that's fine.
+                        let _ = reply.send(result);
+                    }
                 }
             }
         });
 
-        tx
+        (high_tx, low_tx, counters)
     }
 
     /// Queries the size of the canvas.
@@ -111,42 +280,237 @@ impl Sock {
     }
 }
 
+/// A simple token-bucket rate limiter, used to cap outgoing pixels per second.
+struct RateLimiter {
+    state: Mutex<RateLimiterState>,
+    rate: f64,
+    burst: f64,
+}
+
+struct RateLimiterState {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    fn new(rate: f64) -> Self {
+        Self {
+            state: Mutex::new(RateLimiterState {
+                tokens: rate,
+                last_refill: Instant::now(),
+            }),
+            rate,
+            // At least one whole token, so rates below 1/s still send.
+            burst: rate.max(1.0),
+        }
+    }
+
+    /// Waits until a token is available, then consumes it.
+    async fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut state = self.state.lock().await;
+
+                let now = Instant::now();
+                let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+                state.tokens = (state.tokens + elapsed * self.rate).min(self.burst);
+                state.last_refill = now;
+
+                if state.tokens >= 1.0 {
+                    state.tokens -= 1.0;
+                    None
+                } else {
+                    Some(Duration::from_secs_f64((1.0 - state.tokens) / self.rate))
+                }
+            };
+
+            match wait {
+                None => return,
+                Some(wait) => tokio::time::sleep(wait).await,
+            }
+        }
+    }
+}
+
+/// Throughput counters for one connection, shared between its `boot` task
+/// and `Sender::stats`.
+#[derive(Default)]
+pub struct ConnCounters {
+    sent: AtomicU64,
+    dropped: AtomicU64,
+}
+
+/// A single `Sender` connection: the high- and low-priority channels used to
+/// enqueue jobs, plus the counters its `boot` task updates as jobs succeed
+/// or fail.
+struct Conn {
+    high_tx: mpsc::Sender<Job>,
+    low_tx: mpsc::Sender<Job>,
+    counters: Arc<ConnCounters>,
+}
+
+impl Conn {
+    /// The channel to enqueue onto for the given priority.
+    fn tx(&self, priority: RequestPriority) -> &mpsc::Sender<Job> {
+        match priority {
+            RequestPriority::High => &self.high_tx,
+            RequestPriority::Low => &self.low_tx,
+        }
+    }
+
+    /// Combined queue depth across both priority tiers.
+    fn queue_depth(&self) -> usize {
+        (CHANNEL_CAPACITY - self.high_tx.capacity()) + (CHANNEL_CAPACITY - self.low_tx.capacity())
+    }
+}
+
+/// A snapshot of one connection's health, as reported by `Sender::stats`.
+#[derive(Copy, Clone, Debug)]
+pub struct ConnStats {
+    /// Number of jobs currently queued but not yet sent on this connection.
+    pub queue_depth: usize,
+    /// Total jobs successfully handed to the socket over its lifetime.
+    pub sent: u64,
+    /// Total jobs that failed to enqueue (e.g. the socket task had died) or
+    /// that the socket task failed to send or get a reply for.
+    pub dropped: u64,
+}
+
+/// A snapshot of every connection's health, as reported by `Sender::stats`.
+#[derive(Clone, Debug)]
+pub struct Stats {
+    pub connections: Vec<ConnStats>,
+}
+
 /// Handles mass-sending pixels to Pixelflut servers.
 pub struct Sender {
     pub sock: Sock,
-    txs: Vec<mpsc::Sender<Cmd>>,
+    conns: Vec<Conn>,
+    rate_limiter: Option<RateLimiter>,
 }
 
 impl Sender {
-    /// Connects to a Pixelflut server.
-    pub async fn connect<A>(addr: A, n_sender_socks: usize) -> Result<Self>
+    /// Connects to a Pixelflut server, optionally tiling via `offset` and
+    /// rate-limiting outgoing pixels to `max_pps`.
+    pub async fn connect<A>(
+        addr: A,
+        n_sender_socks: usize,
+        offset: Option<Vec2>,
+        max_pps: Option<f64>,
+    ) -> Result<Self>
     where
         A: ToSocketAddrs,
     {
-        let mut txs = Vec::new();
+        let mut conns = Vec::new();
 
         assert!(n_sender_socks > 0, "you must spawn at least one socket");
 
+        // Negotiated once, on its own throwaway connection, then applied to
+        // every real socket below rather than probed on each of them.
+        let encoding = Sock::negotiate_encoding(&addr).await?;
+
         for _ in 0..=n_sender_socks {
-            let sock = Sock::connect(&addr).await?;
-            txs.push(sock.boot());
+            let mut sock = Sock::connect(&addr).await?;
+            sock.encoding = encoding;
+            if let Some(offset) = offset {
+                sock.send(Cmd::Offset(offset)).await?;
+            }
+            let (high_tx, low_tx, counters) = sock.boot();
+            conns.push(Conn {
+                high_tx,
+                low_tx,
+                counters,
+            });
+        }
+
+        let mut sock = Sock::connect(&addr).await?;
+        sock.encoding = encoding;
+        if let Some(offset) = offset {
+            sock.send(Cmd::Offset(offset)).await?;
         }
 
         Ok(Self {
-            sock: Sock::connect(&addr).await?,
-            txs,
+            sock,
+            conns,
+            rate_limiter: max_pps.map(RateLimiter::new),
         })
     }
 
-    /// Pick a transmitter to use to interact with the server.
-    fn pick_tx(&self) -> &mpsc::Sender<Cmd> {
-        let mut rng = thread_rng();
-        self.txs.choose(&mut rng).unwrap()
+    /// Pick a connection to use to interact with the server, preferring
+    /// whichever has the most free capacity (i.e. the shortest combined
+    /// queue) instead of picking uniformly at random.
+    fn pick_conn(&self) -> &Conn {
+        self.conns
+            .iter()
+            .min_by_key(|conn| conn.queue_depth())
+            .unwrap()
     }
 
-    /// Enqueue a Pixelflut command to be sent to the server.
+    /// Waits for `--max-pps` headroom, if a rate limiter is configured.
+    async fn acquire_rate_limit(&self) {
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.acquire().await;
+        }
+    }
+
+    /// Enqueue a Pixelflut command at `RequestPriority::Low`, as before
+    /// priority tiers existed.
     pub async fn send(&self, cmd: Cmd) -> Result<()> {
-        self.pick_tx().send(cmd).await?;
+        self.send_with_priority(cmd, RequestPriority::Low).await
+    }
+
+    /// Enqueue a Pixelflut command to be sent to the server at the given
+    /// priority. Each connection drains its high-priority queue first, so a
+    /// `High` command jumps ahead of any already-queued `Low` ones.
+    pub async fn send_with_priority(&self, cmd: Cmd, priority: RequestPriority) -> Result<()> {
+        self.acquire_rate_limit().await;
+
+        let conn = self.pick_conn();
+        if let Err(err) = conn.tx(priority).send(Job::Fire(cmd)).await {
+            conn.counters.dropped.fetch_add(1, Ordering::Relaxed);
+            return Err(err.into());
+        }
         Ok(())
     }
+
+    /// Enqueue a round-trip command at `RequestPriority::Low` and await its
+    /// response, e.g. reading back the current color of a pixel with
+    /// `Cmd::GetPx`.
+    pub async fn request(&self, cmd: Cmd) -> Result<Rgb> {
+        self.request_with_priority(cmd, RequestPriority::Low).await
+    }
+
+    /// Enqueue a round-trip command at the given priority and await its
+    /// response.
+    pub async fn request_with_priority(&self, cmd: Cmd, priority: RequestPriority) -> Result<Rgb> {
+        self.acquire_rate_limit().await;
+
+        let (reply_tx, reply_rx) = oneshot::channel();
+
+        let conn = self.pick_conn();
+        if let Err(err) = conn.tx(priority).send(Job::Request(cmd, reply_tx)).await {
+            conn.counters.dropped.fetch_add(1, Ordering::Relaxed);
+            return Err(err.into());
+        }
+
+        reply_rx
+            .await
+            .map_err(|_| anyhow::anyhow!("connection dropped before replying to {:?}", cmd))?
+    }
+
+    /// Reports per-connection queue depth, throughput counters, and dropped
+    /// command counts, suitable for periodic printing.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            connections: self
+                .conns
+                .iter()
+                .map(|conn| ConnStats {
+                    queue_depth: conn.queue_depth(),
+                    sent: conn.counters.sent.load(Ordering::Relaxed),
+                    dropped: conn.counters.dropped.load(Ordering::Relaxed),
+                })
+                .collect(),
+        }
+    }
 }
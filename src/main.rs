@@ -1,13 +1,23 @@
 use std::convert::TryInto;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use image::imageops::FilterType;
-use image::GenericImageView;
+use image::{AnimationDecoder, GenericImageView};
 use structopt::StructOpt;
 
-use blamejules::{Cmd, Rgb, Sender, Vec2};
+use blamejules::{Cmd, RequestPriority, Rgb, Sender, Vec2};
+
+/// Parses a `X,Y` pair, e.g. `"100,200"`, into a `Vec2`.
+fn parse_vec2(s: &str) -> Result<Vec2> {
+    let (x, y) = s
+        .split_once(',')
+        .ok_or_else(|| anyhow::anyhow!("expected a coordinate pair like X,Y, got {:?}", s))?;
+    Ok(Vec2(x.trim().parse()?, y.trim().parse()?))
+}
 
 #[derive(StructOpt)]
 #[structopt(name = "blamejules", about = "pixelflut client")]
@@ -16,9 +26,35 @@ struct Opt {
     #[structopt(short, long)]
     server: String,
 
-    /// Path to an image to stretch and paint onto the entire canvas
+    /// Path to an image to stretch and paint onto the window (the entire
+    /// canvas unless --window-size is given). Mutually exclusive with --frames
+    #[structopt(long)]
+    stretch_image: Option<PathBuf>,
+
+    /// Path to a frame source to loop-paint at --fps: a directory of images
+    /// (sorted by filename), an animated GIF, or a video file (decoded via
+    /// an external `ffmpeg`). Mutually exclusive with --stretch-image
     #[structopt(long)]
-    stretch_image: PathBuf,
+    frames: Option<PathBuf>,
+
+    /// Frames per second to play --frames at
+    #[structopt(long, default_value = "24")]
+    fps: f64,
+
+    /// Keep looping --frames instead of stopping after one pass
+    #[structopt(long = "loop")]
+    loop_playback: bool,
+
+    /// Tile onto a sub-window of the canvas at X,Y: sends `OFFSET` on every
+    /// connection so the server translates our coordinates, letting several
+    /// instances paint disjoint regions of one shared wall
+    #[structopt(long, parse(try_from_str = parse_vec2))]
+    offset: Option<Vec2>,
+
+    /// Size of the window to paint into as WIDTH,HEIGHT, used together with
+    /// --offset; defaults to the full canvas size reported by the server
+    #[structopt(long, parse(try_from_str = parse_vec2))]
+    window_size: Option<Vec2>,
 
     /// The number of simultaneous connections used to paint pixels
     #[structopt(short = "c", long, default_value = "4")]
@@ -36,6 +72,173 @@ struct Opt {
     /// The size to resize images down to when crunching
     #[structopt(long, default_value = "16")]
     crunch_size: u32,
+
+    /// Read back each pixel's current color before painting it and skip
+    /// pixels that already match. With --stretch-image, one full fill is sent
+    /// without read-back first, then read-back passes repeat continuously to
+    /// repair pixels that drift away (e.g. other painters racing us); with
+    /// --frames, each frame's changed pixels are read back once
+    #[structopt(long)]
+    diff: bool,
+
+    /// Per-channel tolerance used by --diff to decide whether a pixel
+    /// already matches closely enough to skip
+    #[structopt(long, default_value = "0")]
+    diff_tolerance: u8,
+
+    /// Cap outgoing pixels per second (token-bucket limited) so we degrade
+    /// gracefully instead of flooding the server or the network
+    #[structopt(long)]
+    max_pps: Option<f64>,
+}
+
+/// Whether `a` and `b` are close enough, per-channel, to skip repainting.
+fn pixels_match(a: Rgb, b: Rgb, tolerance: u8) -> bool {
+    let close = |x: u8, y: u8| x.abs_diff(y) <= tolerance;
+    close(a.0, b.0) && close(a.1, b.1) && close(a.2, b.2)
+}
+
+/// Where the frames behind a `Frames` iterator come from.
+enum FrameSource {
+    /// Decoded GIF frames, held in memory up front.
+    Images(Vec<image::DynamicImage>),
+    /// Paths to individual frame images, sorted by filename, loaded lazily.
+    Paths(Vec<PathBuf>),
+}
+
+/// Loops over the frames of a directory of images, an animated GIF, or a
+/// video (decoded to frames via `ffmpeg` ahead of time), cycling forever
+/// when `loop_playback` is set.
+struct Frames {
+    source: FrameSource,
+    index: usize,
+    loop_playback: bool,
+}
+
+impl Frames {
+    /// Opens `path` as a frame source, detecting directories and `.gif`
+    /// files, and falling back to decoding it as a video via `ffmpeg`.
+    fn open(path: &Path, fps: f64, loop_playback: bool) -> Result<Self> {
+        let source = if path.is_dir() {
+            FrameSource::Paths(sorted_dir_entries(path)?)
+        } else if path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.eq_ignore_ascii_case("gif"))
+            .unwrap_or(false)
+        {
+            FrameSource::Images(decode_gif_frames(path)?)
+        } else {
+            let frames_dir = decode_video_frames(path, fps)?;
+            FrameSource::Paths(sorted_dir_entries(&frames_dir)?)
+        };
+
+        Ok(Self {
+            source,
+            index: 0,
+            loop_playback,
+        })
+    }
+
+    fn len(&self) -> usize {
+        match &self.source {
+            FrameSource::Images(images) => images.len(),
+            FrameSource::Paths(paths) => paths.len(),
+        }
+    }
+}
+
+impl Iterator for Frames {
+    type Item = Result<image::DynamicImage>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index >= self.len() {
+            if self.loop_playback {
+                self.index = 0;
+            } else {
+                return None;
+            }
+        }
+
+        let i = self.index;
+        self.index += 1;
+
+        Some(match &self.source {
+            FrameSource::Images(images) => Ok(images[i].clone()),
+            FrameSource::Paths(paths) => {
+                image::open(&paths[i]).with_context(|| format!("opening frame {:?}", paths[i]))
+            }
+        })
+    }
+}
+
+/// Lists a directory's entries, sorted by filename, for frame ordering.
+fn sorted_dir_entries(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)
+        .with_context(|| format!("reading frames directory {:?}", dir))?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+/// Decodes every frame of an animated GIF up front.
+fn decode_gif_frames(path: &Path) -> Result<Vec<image::DynamicImage>> {
+    let file = std::fs::File::open(path).with_context(|| format!("opening gif {:?}", path))?;
+    let decoder = image::codecs::gif::GifDecoder::new(file)?;
+    decoder
+        .into_frames()
+        .map(|frame| Ok(image::DynamicImage::ImageRgba8(frame?.into_buffer())))
+        .collect()
+}
+
+/// Shells out to `ffmpeg` to decode a video into a fresh directory of PNG
+/// frames sampled at `fps`, returning that directory.
+fn decode_video_frames(path: &Path, fps: f64) -> Result<PathBuf> {
+    let unique = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let frames_dir = std::env::temp_dir().join(format!(
+        "blamejules-frames-{}-{}",
+        std::process::id(),
+        unique
+    ));
+    std::fs::create_dir_all(&frames_dir)
+        .with_context(|| format!("creating frame output dir {:?}", frames_dir))?;
+
+    let status = Command::new("ffmpeg")
+        .arg("-i")
+        .arg(path)
+        .arg("-vf")
+        .arg(format!("fps={}", fps))
+        .arg(frames_dir.join("frame_%06d.png"))
+        .status()
+        .context("failed to launch ffmpeg; is it installed and on $PATH?")?;
+
+    if !status.success() {
+        anyhow::bail!("ffmpeg exited with {}", status);
+    }
+
+    Ok(frames_dir)
+}
+
+/// Returns only the `(Vec2, Rgb)` pairs in `current` that differ from the
+/// pixel at the same coordinate in `previous`, so only changed pixels are
+/// repainted between consecutive frames. With no `previous`, every pixel
+/// is considered changed.
+fn diff_frame(previous: Option<&[(Vec2, Rgb)]>, current: &[(Vec2, Rgb)]) -> Vec<(Vec2, Rgb)> {
+    match previous {
+        None => current.to_vec(),
+        Some(previous) => current
+            .iter()
+            .zip(previous.iter())
+            .filter(|(cur, prev)| !pixels_match(cur.1, prev.1, 0))
+            .map(|(cur, _)| *cur)
+            .collect(),
+    }
 }
 
 fn apply_options_to_image(
@@ -58,20 +261,186 @@ fn apply_options_to_image(
     Ok(img)
 }
 
+async fn send_chunk(
+    sender: Arc<Sender>,
+    chunk: &[(Vec2, Rgb)],
+    diff: bool,
+    tolerance: u8,
+    priority: RequestPriority,
+) {
+    for (coordinate, pixel) in chunk {
+        if diff {
+            match sender
+                .request_with_priority(Cmd::GetPx(*coordinate), priority)
+                .await
+            {
+                Ok(current) if pixels_match(current, *pixel, tolerance) => continue,
+                Err(err) => eprintln!(
+                    "failed to read back pixel @ {:?}, painting anyway: {:?}",
+                    coordinate, err
+                ),
+                Ok(_) => {}
+            }
+        }
+
+        if let Err(err) = sender
+            .send_with_priority(Cmd::SetPx(*coordinate, *pixel), priority)
+            .await
+        {
+            eprintln!(
+                "failed to paint pixel @ {:?} with {:?}, because: {:?}",
+                coordinate, pixel, err
+            );
+        }
+    }
+}
+
+async fn paint_once(
+    sender: Arc<Sender>,
+    pixels: &[(Vec2, Rgb)],
+    chunk_size: usize,
+    diff: bool,
+    tolerance: u8,
+    priority: RequestPriority,
+) {
+    let futures = pixels.chunks(chunk_size).map(|chunk| {
+        let sender = Arc::clone(&sender);
+        async move {
+            send_chunk(sender, chunk, diff, tolerance, priority).await;
+        }
+    });
+
+    futures::future::join_all(futures).await;
+}
+
+/// Drives a `Frames` source at `opt.fps`, sending only the pixels that
+/// changed since the last frame that finished painting within its budget.
+/// Each frame gets one frame-interval budget; on a miss we move on to the
+/// next frame immediately so playback stays caught up with real time, but
+/// jobs already queued on a connection still drain in the background, so a
+/// missed frame's paint isn't fully cancelled, just no longer waited on.
+async fn play_frames(
+    frames_path: &Path,
+    opt: &Opt,
+    window_size: Vec2,
+    sender: Arc<Sender>,
+    chunk_size: usize,
+) -> Result<()> {
+    let frame_duration = Duration::from_secs_f64(1.0 / opt.fps);
+    let frames = Frames::open(frames_path, opt.fps, opt.loop_playback)?;
+
+    println!(
+        "playing frames from {:?} at {} fps (loop: {})...",
+        frames_path, opt.fps, opt.loop_playback
+    );
+
+    let mut previous: Option<Vec<(Vec2, Rgb)>> = None;
+
+    for (i, frame) in frames.enumerate() {
+        let deadline = tokio::time::Instant::now() + frame_duration;
+
+        let frame = apply_options_to_image(opt, window_size, frame?)?;
+        let img_buffer = frame.to_rgb8();
+        let current: Vec<(Vec2, Rgb)> = img_buffer
+            .enumerate_pixels()
+            .map(|(x, y, color)| (Vec2(x, y), (*color).into()))
+            .collect();
+
+        let changed = diff_frame(previous.as_deref(), &current);
+        println!(
+            "frame {}: {} / {} pixels changed",
+            i,
+            changed.len(),
+            current.len()
+        );
+
+        let paint = paint_once(
+            Arc::clone(&sender),
+            &changed,
+            chunk_size,
+            opt.diff,
+            opt.diff_tolerance,
+            RequestPriority::Low,
+        );
+        tokio::pin!(paint);
+        let painted = tokio::select! {
+            _ = &mut paint => true,
+            _ = tokio::time::sleep_until(deadline) => {
+                eprintln!("frame {} missed its {:?} budget; moving to the next frame", i, frame_duration);
+                false
+            }
+        };
+        tokio::time::sleep_until(deadline).await;
+
+        // Only advance the diff baseline on a frame that actually finished
+        // painting. Otherwise pixels this frame queued but never sent would
+        // look "unchanged" against the next frame and get skipped forever.
+        if painted {
+            previous = Some(current);
+        }
+    }
+
+    Ok(())
+}
+
 async fn go(opt: Opt, mut sender: Sender) -> Result<()> {
-    let img = image::open(&opt.stretch_image).unwrap();
+    let canvas_size = sender.sock.query_size().await?;
+    let window_size = opt.window_size.unwrap_or(canvas_size);
+    let Vec2(width, height) = window_size;
+    println!(
+        "canvas: {}x{} ({} pixels), window: {}x{}",
+        canvas_size.0,
+        canvas_size.1,
+        canvas_size.0 * canvas_size.1,
+        width,
+        height
+    );
+
+    let arc = Arc::new(sender);
+    let diff = opt.diff;
+    let diff_tolerance = opt.diff_tolerance;
+
+    let stats_printer = {
+        let sender = Arc::clone(&arc);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(2));
+            loop {
+                interval.tick().await;
+                for (i, conn) in sender.stats().connections.iter().enumerate() {
+                    println!(
+                        "conn {}: sent={} queue_depth={} dropped={}",
+                        i, conn.sent, conn.queue_depth, conn.dropped
+                    );
+                }
+            }
+        })
+    };
+
+    // Evenly divide the image into chunks. A window smaller than --chunks
+    // (e.g. a tiny --window-size) would otherwise floor-divide to zero and
+    // panic the pixels.chunks(0) call inside paint_once.
+    let chunk_size: usize = TryInto::<usize>::try_into((width * height) / opt.chunks)
+        .unwrap()
+        .max(1);
+
+    if let Some(frames_path) = &opt.frames {
+        play_frames(frames_path, &opt, window_size, arc, chunk_size).await?;
+        stats_printer.abort();
+        return Ok(());
+    }
+
+    let stretch_image = opt
+        .stretch_image
+        .as_ref()
+        .expect("main validated exactly one of --stretch-image/--frames is set");
+    let img = image::open(stretch_image)?;
     println!(
         "opened image, dimensions: {:?}, color: {:?}",
         img.dimensions(),
         img.color()
     );
 
-    let canvas_size = sender.sock.query_size().await?;
-    let Vec2(width, height) = canvas_size;
-    let total_size = width * height;
-    println!("canvas: {}x{} ({} pixels)", width, height, total_size);
-
-    let img = apply_options_to_image(&opt, canvas_size, img)?;
+    let img = apply_options_to_image(&opt, window_size, img)?;
     let img_buffer = img.to_rgb8();
 
     let pixels: Vec<(Vec2, Rgb)> = img_buffer
@@ -79,41 +448,54 @@ async fn go(opt: Opt, mut sender: Sender) -> Result<()> {
         .map(|(x, y, color)| (Vec2(x, y), (*color).into()))
         .collect();
 
-    let arc = Arc::new(sender);
-
-    // Evenly divide the image into chunks.
-    let chunk_size: usize = (total_size / opt.chunks).try_into().unwrap();
-    let chunks = pixels.chunks(chunk_size);
+    if diff {
+        println!(
+            "sending initial fill (chunks: {}, chunk size: {}, low priority)...",
+            opt.chunks, chunk_size
+        );
+        paint_once(
+            Arc::clone(&arc),
+            &pixels,
+            chunk_size,
+            false,
+            0,
+            RequestPriority::Low,
+        )
+        .await;
 
-    println!(
-        "sending (chunks: {}, chunk size: {})...",
-        opt.chunks, chunk_size
-    );
-
-    async fn send_chunk(sender: Arc<Sender>, chunk: &[(Vec2, Rgb)]) {
-        for (coordinate, pixel) in chunk {
-            if let Err(err) = sender.send(Cmd::SetPx(*coordinate, *pixel)).await {
-                eprintln!(
-                    "failed to paint pixel @ {:?} with {:?}, because: {:?}",
-                    coordinate, pixel, err
-                );
-            }
+        println!(
+            "repairing (chunks: {}, chunk size: {}, tolerance: {}, high priority)... press ctrl-c to stop",
+            opt.chunks, chunk_size, diff_tolerance
+        );
+        loop {
+            paint_once(
+                Arc::clone(&arc),
+                &pixels,
+                chunk_size,
+                diff,
+                diff_tolerance,
+                RequestPriority::High,
+            )
+            .await;
         }
+    } else {
+        println!(
+            "sending (chunks: {}, chunk size: {})...",
+            opt.chunks, chunk_size
+        );
+        paint_once(
+            arc,
+            &pixels,
+            chunk_size,
+            diff,
+            diff_tolerance,
+            RequestPriority::Low,
+        )
+        .await;
+        stats_printer.abort();
+        println!("done!");
     }
 
-    // Concurrently send the pixels from each chunk.
-    let futures = chunks.map(|chunk| {
-        let sender = Arc::clone(&arc);
-        async move {
-            send_chunk(sender, chunk).await;
-        }
-    });
-
-    // Wait for all chunks to finish sending pixels.
-    futures::future::join_all(futures).await;
-
-    println!("done!");
-
     Ok(())
 }
 
@@ -121,13 +503,17 @@ async fn go(opt: Opt, mut sender: Sender) -> Result<()> {
 async fn main() -> Result<()> {
     let opt = Opt::from_args();
 
+    if opt.stretch_image.is_some() == opt.frames.is_some() {
+        anyhow::bail!("pass exactly one of --stretch-image or --frames");
+    }
+
     let addr = tokio::net::lookup_host(&opt.server)
         .await?
         .next()
         .ok_or_else(|| anyhow::anyhow!("failed to lookup server"))?;
 
     print!("connecting ({} + 1 sockets)... ", opt.connections);
-    let sender = Sender::connect(addr, opt.connections).await?;
+    let sender = Sender::connect(addr, opt.connections, opt.offset, opt.max_pps).await?;
     println!("connected.");
 
     go(opt, sender).await?;